@@ -4,6 +4,7 @@
 extern crate clap;
 #[macro_use]
 extern crate failure;
+extern crate regex;
 #[macro_use]
 extern crate slog;
 extern crate slog_async;
@@ -15,9 +16,13 @@ use std::{
     fs::File,
     io::{self, Read, Write},
     process,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
 };
 
 use clap::{App, Arg};
+use regex::Regex;
 use slog::{Drain, Level, Logger};
 use slog_async::Async;
 use slog_term::{CompactFormat, TermDecorator};
@@ -41,8 +46,19 @@ fn run() -> Result<(), failure::Error> {
                 .long("elf")
                 .value_name("ELF")
                 .takes_value(true)
-                .required(true)
+                .required_unless("symbols")
+                .conflicts_with("symbols")
                 .help("ELF file whose symbol table will be used to decode the logs"),
+        ).arg(
+            Arg::with_name("symbols")
+                .long("symbols")
+                .value_name("FILE")
+                .takes_value(true)
+                .required_unless("elf")
+                .help(
+                    "Plain-text symbol map (`ADDRESS SEVERITY CONTENT` per line) to decode \
+                     against instead of an ELF's `.symtab`, for stripped release images",
+                ),
         ).arg(
             Arg::with_name("filter")
                 .short("f")
@@ -51,6 +67,63 @@ fn run() -> Result<(), failure::Error> {
                 .takes_value(true)
                 .required(false)
                 .help("Decodes only messages of this severity or higher (default: trace)"),
+        ).arg(
+            Arg::with_name("grep")
+                .long("grep")
+                .value_name("REGEX")
+                .takes_value(true)
+                .required(false)
+                .conflicts_with("contains")
+                .help("Only shows messages whose content matches this regular expression"),
+        ).arg(
+            Arg::with_name("contains")
+                .long("contains")
+                .value_name("STR")
+                .takes_value(true)
+                .required(false)
+                .conflicts_with("grep")
+                .help("Only shows messages whose content contains this substring (cheaper than --grep)"),
+        ).arg(
+            Arg::with_name("grep-v")
+                .long("grep-v")
+                .help("Inverts the `--grep` / `--contains` match, excluding messages that match"),
+        ).arg(
+            Arg::with_name("output-format")
+                .long("output-format")
+                .value_name("FORMAT")
+                .takes_value(true)
+                .required(false)
+                .possible_values(&["human", "json"])
+                .default_value("human")
+                .help("Selects the output format (default: human)"),
+        ).arg(
+            Arg::with_name("legacy-single-byte")
+                .long("legacy-single-byte")
+                .help(
+                    "Decodes message addresses as a single byte each, instead of as a LEB128 \
+                     varint (the original wire format, limited to 256 distinct messages)",
+                ),
+        ).arg(
+            Arg::with_name("timestamped")
+                .long("timestamped")
+                .help(
+                    "Expects each record to be a `[varint micros-since-boot][address]` tuple and \
+                     prints the device-supplied timestamp instead of the host's wall-clock time",
+                ),
+        ).arg(
+            Arg::with_name("follow")
+                .long("follow")
+                .help(
+                    "Keeps reading past end of input, printing messages as bytes arrive, like \
+                     `tail -f` (for a live serial link rather than a one-shot file decode)",
+                ),
+        ).arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .help(
+                    "Emits a diagnostic for addresses not found in `messages`, instead of \
+                     silently ignoring them; helps spot framing desync on a live `--follow` link",
+                ),
         ).arg(
             Arg::with_name("LOGFILE")
                 .required(false)
@@ -68,45 +141,186 @@ fn run() -> Result<(), failure::Error> {
         None => Level::Trace,
     };
 
-    let mut bytes = vec![];
-    File::open(matches.value_of("elf").unwrap())?.read_to_end(&mut bytes)?;
-    let elf = ElfFile::new(&bytes).map_err(failure::err_msg)?;
+    let output_format = match matches.value_of("output-format") {
+        Some("json") => OutputFormat::Json,
+        _ => OutputFormat::Human,
+    };
 
-    let messages = if let Some(symtab) = elf.find_section_by_name(".symtab") {
-        match symtab.get_data(&elf).map_err(failure::err_msg)? {
-            SectionData::SymbolTable32(entries) => process_symtab(entries, &elf)?,
-            SectionData::SymbolTable64(entries) => process_symtab(entries, &elf)?,
-            _ => bail!("malformed .symtab section"),
-        }
+    let grep_invert = matches.is_present("grep-v");
+    let content_filter: Option<ContentFilter> = if let Some(pattern) = matches.value_of("grep") {
+        let re = Regex::new(pattern)?;
+        Some(Box::new(move |content: &str| re.is_match(content) != grep_invert))
+    } else if let Some(needle) = matches.value_of("contains") {
+        let needle = needle.to_owned();
+        Some(Box::new(move |content: &str| content.contains(&needle) != grep_invert))
     } else {
-        bail!(".symtab section not found");
+        None
+    };
+
+    let messages = if let Some(symbols) = matches.value_of("symbols") {
+        process_symbols_file(symbols)?
+    } else {
+        let mut bytes = vec![];
+        File::open(matches.value_of("elf").unwrap())?.read_to_end(&mut bytes)?;
+        let elf = ElfFile::new(&bytes).map_err(failure::err_msg)?;
+
+        if let Some(symtab) = elf.find_section_by_name(".symtab") {
+            match symtab.get_data(&elf).map_err(failure::err_msg)? {
+                SectionData::SymbolTable32(entries) => process_symtab(entries, &elf)?,
+                SectionData::SymbolTable64(entries) => process_symtab(entries, &elf)?,
+                _ => bail!("malformed .symtab section"),
+            }
+        } else {
+            bail!(".symtab section not found");
+        }
     };
 
-    let format = CompactFormat::new(TermDecorator::new().stdout().build());
+    let legacy_single_byte = matches.is_present("legacy-single-byte");
+    let timestamped = matches.is_present("timestamped");
+    let follow = matches.is_present("follow");
+    let strict = matches.is_present("strict");
+
     let stdin = io::stdin();
-    let (input, format): (Box<Read>, _) = if let Some(logfile) = matches.value_of("LOGFILE") {
-        (
-            Box::new(File::open(logfile)?),
-            format.use_custom_timestamp(no_timestamp),
-        )
+    let mut input: Box<Read> = if let Some(logfile) = matches.value_of("LOGFILE") {
+        Box::new(File::open(logfile)?)
     } else {
-        (Box::new(stdin.lock()), format.use_local_timestamp())
+        Box::new(stdin.lock())
     };
 
-    let drain = format.build().filter_level(severity).fuse();
-    let logger = Logger::root(Async::new(drain).build().fuse(), o!());
+    match output_format {
+        OutputFormat::Human => {
+            let format = CompactFormat::new(TermDecorator::new().stdout().build());
+
+            if timestamped {
+                // `Logger::root` requires a `Send + Sync + UnwindSafe` drain, which `Async`
+                // can't give us here (the device timestamp shared through this lock must be
+                // consumed by the same record that set it, so the drain has to run inline
+                // rather than on Async's worker thread). `Mutex` gets us those bounds for free
+                // (it's unconditionally `UnwindSafe`) while still formatting synchronously.
+                let device_timestamp = Arc::new(Mutex::new(0u64));
+                let format = {
+                    let device_timestamp = Arc::clone(&device_timestamp);
+                    format.use_custom_timestamp(move |io: &mut Write| {
+                        write!(io, "{}us", *device_timestamp.lock().unwrap())
+                    })
+                };
+
+                let drain = format.build().filter_level(severity).fuse();
+                let drain = Mutex::new(drain).fuse();
+                let logger = Logger::root(drain, o!());
+
+                while let Some((timestamp, address)) =
+                    read_timestamped_address(&mut input, legacy_single_byte, follow)?
+                {
+                    if let Some(message) = messages.get(&address) {
+                        if let Some(ref filter) = content_filter {
+                            if !filter(&message.content) {
+                                continue;
+                            }
+                        }
 
-    for byte in input.bytes() {
-        let address = u64::from(byte?);
+                        *device_timestamp.lock().unwrap() = timestamp;
 
-        if let Some(message) = messages.get(&address) {
-            match message.severity {
-                Level::Error => error!(logger, "{}", message.content),
-                Level::Warning => warn!(logger, "{}", message.content),
-                Level::Info => info!(logger, "{}", message.content),
-                Level::Debug => debug!(logger, "{}", message.content),
-                Level::Trace => trace!(logger, "{}", message.content),
-                _ => {} // unreachable
+                        match message.severity {
+                            Level::Error => error!(logger, "{}", message.content),
+                            Level::Warning => warn!(logger, "{}", message.content),
+                            Level::Info => info!(logger, "{}", message.content),
+                            Level::Debug => debug!(logger, "{}", message.content),
+                            Level::Trace => trace!(logger, "{}", message.content),
+                            _ => {} // unreachable
+                        }
+                    } else if strict {
+                        warn_unknown_address(address);
+                    }
+                }
+            } else {
+                let format = if matches.value_of("LOGFILE").is_some() {
+                    format.use_custom_timestamp(no_timestamp)
+                } else {
+                    format.use_local_timestamp()
+                };
+
+                let drain = format.build().filter_level(severity).fuse();
+                let logger = Logger::root(Async::new(drain).build().fuse(), o!());
+
+                while let Some(address) = read_address(&mut input, legacy_single_byte, follow)? {
+                    if let Some(message) = messages.get(&address) {
+                        if let Some(ref filter) = content_filter {
+                            if !filter(&message.content) {
+                                continue;
+                            }
+                        }
+
+                        match message.severity {
+                            Level::Error => error!(logger, "{}", message.content),
+                            Level::Warning => warn!(logger, "{}", message.content),
+                            Level::Info => info!(logger, "{}", message.content),
+                            Level::Debug => debug!(logger, "{}", message.content),
+                            Level::Trace => trace!(logger, "{}", message.content),
+                            _ => {} // unreachable
+                        }
+                    } else if strict {
+                        warn_unknown_address(address);
+                    }
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            let mut seq: u64 = 0;
+
+            loop {
+                let (timestamp, address) = if timestamped {
+                    match read_timestamped_address(&mut input, legacy_single_byte, follow)? {
+                        Some((timestamp, address)) => (Some(timestamp), address),
+                        None => break,
+                    }
+                } else {
+                    match read_address(&mut input, legacy_single_byte, follow)? {
+                        Some(address) => (None, address),
+                        None => break,
+                    }
+                };
+
+                // `seq` tracks input position, i.e. every successfully decoded record, not just
+                // the ones that end up printed — otherwise a message dropped by a severity/grep
+                // filter is indistinguishable downstream from one that was never decoded at all.
+                let this_seq = seq;
+                seq += 1;
+
+                if let Some(message) = messages.get(&address) {
+                    if let Some(ref filter) = content_filter {
+                        if !filter(&message.content) {
+                            continue;
+                        }
+                    }
+
+                    if !message.severity.is_at_least(severity) {
+                        continue;
+                    }
+
+                    if let Some(timestamp) = timestamp {
+                        writeln!(
+                            stdout,
+                            "{{\"seq\":{},\"timestamp\":{},\"severity\":\"{}\",\"message\":\"{}\"}}",
+                            this_seq,
+                            timestamp,
+                            level_name(message.severity),
+                            json_escape(&message.content)
+                        )?;
+                    } else {
+                        writeln!(
+                            stdout,
+                            "{{\"seq\":{},\"severity\":\"{}\",\"message\":\"{}\"}}",
+                            this_seq,
+                            level_name(message.severity),
+                            json_escape(&message.content)
+                        )?;
+                    }
+                } else if strict {
+                    warn_unknown_address(address);
+                }
             }
         }
     }
@@ -114,14 +328,161 @@ fn run() -> Result<(), failure::Error> {
     Ok(())
 }
 
+enum OutputFormat {
+    Human,
+    Json,
+}
+
+/// A predicate over a decoded message's `content`, built once from `--grep`/`--contains`.
+type ContentFilter = Box<Fn(&str) -> bool>;
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Critical => "critical",
+        Level::Error => "error",
+        Level::Warning => "warning",
+        Level::Info => "info",
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    }
+}
+
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
 fn no_timestamp(_: &mut Write) -> io::Result<()> {
     Ok(())
 }
 
+/// Reports (under `--strict`) an address that decoded successfully but isn't in `messages`,
+/// a sign of framing desync rather than something to silently drop.
+fn warn_unknown_address(address: u64) {
+    eprintln!("stcat: unknown message address {} (possible framing desync)", address);
+}
+
+/// Reads a single byte from `input`.
+///
+/// Returns `Ok(None)` on a clean end of input. In `follow` mode, end of input instead means
+/// "no bytes available yet" and the read is retried until more data arrives, like `tail -f`.
+fn read_byte(input: &mut Read, follow: bool) -> io::Result<Option<u8>> {
+    let mut byte = [0; 1];
+
+    loop {
+        if input.read(&mut byte)? != 0 {
+            return Ok(Some(byte[0]));
+        }
+
+        if !follow {
+            return Ok(None);
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Reads the next message address from `input`.
+///
+/// Returns `Ok(None)` on a clean end of input (no bytes read). When `legacy_single_byte` is set
+/// the address is a single byte, matching the original wire format; otherwise it's a LEB128
+/// unsigned varint: 7 bits per byte, high bit set means "more bytes follow".
+fn read_address(
+    input: &mut Read,
+    legacy_single_byte: bool,
+    follow: bool,
+) -> Result<Option<u64>, failure::Error> {
+    if legacy_single_byte {
+        return Ok(read_byte(input, follow)?.map(u64::from));
+    }
+
+    read_varint(input, follow)
+}
+
+/// Reads a `[varint micros-since-boot][address]` tuple, as produced by `--timestamped` streams.
+///
+/// Returns `Ok(None)` on a clean end of input. A timestamp with no following address is a
+/// truncated record and is reported as an error rather than silently dropped.
+fn read_timestamped_address(
+    input: &mut Read,
+    legacy_single_byte: bool,
+    follow: bool,
+) -> Result<Option<(u64, u64)>, failure::Error> {
+    let timestamp = match read_varint(input, follow)? {
+        Some(timestamp) => timestamp,
+        None => return Ok(None),
+    };
+
+    // Under `--follow` the address can legitimately arrive well after its timestamp (a live
+    // link delivers bytes one at a time), so keep waiting the same way the timestamp did; only
+    // a true, non-following EOF after a parsed timestamp counts as a truncated record.
+    let address = read_address(input, legacy_single_byte, follow)?
+        .ok_or_else(|| failure::err_msg("truncated record: timestamp present but address missing"))?;
+
+    Ok(Some((timestamp, address)))
+}
+
+/// Reads a LEB128 unsigned varint: 7 bits per byte, high bit set means "more bytes follow".
+///
+/// Returns `Ok(None)` on a clean end of input (no bytes read).
+fn read_varint(input: &mut Read, follow: bool) -> Result<Option<u64>, failure::Error> {
+    let mut value: u64 = 0;
+    let mut shift = 0u32;
+    let mut first = true;
+
+    loop {
+        // Continuation bytes retry on end-of-input under `--follow` too: on a live, baud-rate
+        // limited link the rest of a multi-byte varint can arrive well after the first byte,
+        // and that's indistinguishable from true EOF.
+        let byte = match read_byte(input, follow)? {
+            Some(byte) => byte,
+            None => {
+                if first {
+                    return Ok(None);
+                } else {
+                    bail!("truncated varint at end of input");
+                }
+            }
+        };
+        first = false;
+
+        if shift > 63 {
+            bail!("varint is too long (more than 10 bytes / 64 bits)");
+        }
+
+        // The 10th byte only has room for 1 more bit (bits 0..63 are already spoken for); any
+        // of its other payload bits being set means the value doesn't fit in 64 bits.
+        if shift == 63 && byte & 0x7e != 0 {
+            bail!("varint overflows 64 bits");
+        }
+
+        value |= u64::from(byte & 0x7f) << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+
+        shift += 7;
+    }
+}
+
 fn process_symtab<'a, E>(
     entries: &'a [E],
     elf: &'a ElfFile,
-) -> Result<HashMap<u64, Message<'a>>, failure::Error>
+) -> Result<HashMap<u64, Message>, failure::Error>
 where
     E: Entry,
 {
@@ -185,7 +546,64 @@ where
             address,
             Message {
                 severity,
-                content: entry.get_name(&elf).map_err(failure::err_msg)?,
+                content: entry.get_name(&elf).map_err(failure::err_msg)?.to_owned(),
+            },
+        );
+    }
+
+    Ok(messages)
+}
+
+/// Loads a `HashMap<u64, Message>` from a plain-text symbol map, the externally-supplied
+/// alternative to a `.symtab` section for stripped release images.
+///
+/// Each non-empty, non-`#`-comment line is `ADDRESS SEVERITY CONTENT`, e.g.:
+///
+/// ```text
+/// 0x2000 info heater turned on
+/// 4098   debug duty cycle = {}%
+/// ```
+fn process_symbols_file(path: &str) -> Result<HashMap<u64, Message>, failure::Error> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+
+    let mut messages = HashMap::new();
+
+    for (lineno, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let lineno = lineno + 1;
+        let mut rest = line;
+
+        let address = next_field(&mut rest)
+            .ok_or_else(|| failure::err_msg(format!("{}:{}: missing address", path, lineno)))?;
+        let address = parse_address(address)
+            .ok_or_else(|| failure::err_msg(format!("{}:{}: invalid address `{}`", path, lineno, address)))?;
+
+        let severity = next_field(&mut rest)
+            .ok_or_else(|| failure::err_msg(format!("{}:{}: missing severity", path, lineno)))?;
+        let severity = match severity {
+            "error" => Level::Error,
+            "warning" => Level::Warning,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            "trace" => Level::Trace,
+            _ => bail!("{}:{}: invalid severity `{}`", path, lineno, severity),
+        };
+
+        let content = rest.trim_start();
+        if content.is_empty() {
+            bail!("{}:{}: missing message content", path, lineno);
+        }
+
+        messages.insert(
+            address,
+            Message {
+                severity,
+                content: content.to_owned(),
             },
         );
     }
@@ -193,7 +611,33 @@ where
     Ok(messages)
 }
 
-struct Message<'a> {
+/// Pops the next whitespace-delimited field off the front of `rest`, skipping (rather than
+/// splitting on) any run of leading or repeated whitespace, so column-aligned fields parse the
+/// same as single-space-separated ones.
+fn next_field<'a>(rest: &mut &'a str) -> Option<&'a str> {
+    *rest = rest.trim_start();
+
+    if rest.is_empty() {
+        return None;
+    }
+
+    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    let field = &rest[..end];
+    *rest = &rest[end..];
+
+    Some(field)
+}
+
+/// Parses a decimal (`4098`) or `0x`-prefixed hexadecimal (`0x2000`) address.
+fn parse_address(s: &str) -> Option<u64> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        u64::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+struct Message {
     severity: Level,
-    content: &'a str,
+    content: String,
 }